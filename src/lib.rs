@@ -1,18 +1,95 @@
 extern crate clock_ticks;
 
-use std::sync::mpsc::{channel, Sender, Receiver};
-use std::sync::mpsc::TryRecvError::*;
+use std::sync::mpsc::{channel, sync_channel, Sender, SyncSender, Receiver, RecvTimeoutError, TrySendError};
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::thread;
+use std::time::Duration;
 
 use clock_ticks::precise_time_ms;
 use std::boxed::Box;
 
 type FilterFn<T> = Box<Fn(&T) -> bool + Send>;
 
+/// Reports how many matching values were dropped from a bounded subscription
+/// because the consumer fell behind.
+///
+/// Sent ahead of the next matching value once the subscription's buffer has room. The report
+/// itself takes a buffer slot, so if it takes the last free one, that value is dropped as well
+/// and counted in a further `Lagged` sent before a later value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lagged {
+    pub skipped: usize
+}
+
+struct BoundedSub<T> {
+    filter: FilterFn<T>,
+    sender: SyncSender<Result<T, Lagged>>,
+    skipped: Cell<usize>
+}
+
+struct Pool<T> {
+    filter: FilterFn<T>,
+    members: Vec<Sender<T>>,
+    cursor: usize
+}
+
+enum Msg<T> {
+    Sub(FilterFn<T>, Sender<T>),
+    SubLatest(FilterFn<T>, Sender<T>),
+    SubTimeout(u64, FilterFn<T>, Sender<T>),
+    SubBounded(FilterFn<T>, SyncSender<Result<T, Lagged>>),
+    PoolNew(usize, FilterFn<T>),
+    PoolJoin(usize, Sender<T>),
+    Value(T),
+    SourceAdded,
+    SourceDone
+}
+
+/// Forwards values from `source` into the dispatcher's merged message channel until `source`
+/// disconnects, at which point it reports `Msg::SourceDone` and exits.
+fn spawn_forwarder<T: Send + 'static>(source: Receiver<T>, msg_s: Sender<Msg<T>>) {
+    thread::spawn(move || {
+        loop {
+            match source.recv() {
+                Ok(value) => {
+                    if msg_s.send(Msg::Value(value)).is_err() {
+                        return;
+                    }
+                }
+                Err(_) => {
+                    let _ = msg_s.send(Msg::SourceDone);
+                    return;
+                }
+            }
+        }
+    });
+}
+
 #[derive(Clone)]
 pub struct Requester<T: Send> {
-    subscribe_no_timeout: Sender<(FilterFn<T>, Sender<T>)>,
-    subscribe_timeout: Sender<(u64, FilterFn<T>, Sender<T>)>
+    msg_s: Sender<Msg<T>>,
+    next_pool_id: Arc<AtomicUsize>
+}
+
+/// A handle to a work-distribution pool created by `Requester::request_pool`.
+///
+/// Each registered worker receives a disjoint subset of the matching values: every value is
+/// delivered to exactly one live worker, round-robin, instead of being broadcast to all of them.
+pub struct PoolHandle<T: Send> {
+    pool_id: usize,
+    msg_s: Sender<Msg<T>>
+}
+
+impl <T: Clone + Send + 'static> PoolHandle<T> {
+    /// Register a new worker under this pool and return its Receiver<T>.
+    pub fn worker(&self) -> Receiver<T> {
+        let (sx, rx) = channel();
+        self.msg_s.send(Msg::PoolJoin(self.pool_id, sx)).unwrap();
+        rx
+    }
 }
 
 impl <T: Clone + Send + 'static> Requester<T> {
@@ -24,48 +101,70 @@ impl <T: Clone + Send + 'static> Requester<T> {
     pub fn new(source: Receiver<T>) -> (Requester<T>, Receiver<T>) {
         let mut without_timeouts: Vec<(FilterFn<T>, Sender<T>)> = Vec::new();
         let mut with_timeouts: Vec<(u64, FilterFn<T>, Sender<T>)> = Vec::new();
+        let mut bounded: Vec<BoundedSub<T>> = Vec::new();
+        let mut pools: HashMap<usize, Pool<T>> = HashMap::new();
+        let mut last_value: Option<T> = None;
+        let mut has_latest_subscribers = false;
 
-        let (nt_s, nt_r) = channel();
-        let (t_s, t_r) = channel();
+        let (msg_s, msg_r) = channel();
 
         let (forward_s, forward_r) = channel();
         let mut forward_s = Some(forward_s);
 
-        let mut no_more_subscribers = false;
+        // The dispatcher below only ever blocks on `msg_r`, so forward the
+        // source's values into it from a dedicated thread instead of polling
+        // `source` directly.
+        spawn_forwarder(source, msg_s.clone());
 
         thread::spawn(move || {
+            let mut live_sources: usize = 1;
+
             loop {
-                // Get new subscribers (no timeout)
-                loop {
-                    match nt_r.try_recv() {
-                        Ok(sub) => without_timeouts.push(sub),
-                        Err(Empty) => break,
-                        Err(Disconnected) => {
-                            no_more_subscribers = true;
-                            break;
-                        }
+                let next = match with_timeouts.iter().map(|&(deadline, _, _)| deadline).min() {
+                    Some(deadline) => {
+                        let now = precise_time_ms();
+                        let wait = if deadline > now { deadline - now } else { 0 };
+                        msg_r.recv_timeout(Duration::from_millis(wait))
                     }
-                }
+                    None => msg_r.recv().map_err(|_| RecvTimeoutError::Disconnected)
+                };
 
-                // Get new subscribers (timeout)
-                loop {
-                    match t_r.try_recv() {
-                        Ok(sub) => with_timeouts.push(sub),
-                        Err(Empty) => break,
-                        Err(Disconnected) => {
-                            no_more_subscribers = true;
-                            break;
+                match next {
+                    Ok(Msg::Sub(filter, sender)) => without_timeouts.push((filter, sender)),
+                    Ok(Msg::SubLatest(filter, sender)) => {
+                        has_latest_subscribers = true;
+                        let deliver = match last_value {
+                            Some(ref value) => filter(value),
+                            None => false
+                        };
+                        if deliver {
+                            if let Some(ref value) = last_value {
+                                let _ = sender.send(value.clone());
+                            }
                         }
+                        without_timeouts.push((filter, sender));
                     }
-                }
-
-                // Get rid of timed out receivers
-                let current = precise_time_ms();
-                with_timeouts.retain(|&(deadline, _, _)| deadline >= current);
+                    Ok(Msg::SubTimeout(deadline, filter, sender)) => with_timeouts.push((deadline, filter, sender)),
+                    Ok(Msg::SubBounded(filter, sender)) => bounded.push(BoundedSub {
+                        filter: filter,
+                        sender: sender,
+                        skipped: Cell::new(0)
+                    }),
+                    Ok(Msg::PoolNew(pool_id, filter)) => {
+                        pools.insert(pool_id, Pool { filter: filter, members: Vec::new(), cursor: 0 });
+                    }
+                    Ok(Msg::PoolJoin(pool_id, sender)) => {
+                        if let Some(pool) = pools.get_mut(&pool_id) {
+                            pool.members.push(sender);
+                        }
+                    }
+                    Ok(Msg::SourceAdded) => live_sources += 1,
+                    Ok(Msg::SourceDone) => live_sources -= 1,
+                    Ok(Msg::Value(value)) => {
+                        if has_latest_subscribers {
+                            last_value = Some(value.clone());
+                        }
 
-                // Get a value and send it out to all the listeners
-                match source.try_recv() {
-                    Ok(value) => {
                         without_timeouts.retain(|&(ref filter, ref sender)| {
                             if filter(&value) {
                                 let res = sender.send(value.clone());
@@ -84,6 +183,55 @@ impl <T: Clone + Send + 'static> Requester<T> {
                             }
                         });
 
+                        bounded.retain(|sub| {
+                            if !(sub.filter)(&value) {
+                                return true;
+                            }
+
+                            let skipped = sub.skipped.get();
+                            if skipped > 0 {
+                                match sub.sender.try_send(Err(Lagged { skipped: skipped })) {
+                                    Ok(()) => sub.skipped.set(0),
+                                    Err(TrySendError::Full(_)) => {
+                                        sub.skipped.set(skipped + 1);
+                                        return true;
+                                    }
+                                    Err(TrySendError::Disconnected(_)) => return false
+                                }
+                            }
+
+                            match sub.sender.try_send(Ok(value.clone())) {
+                                Ok(()) => true,
+                                Err(TrySendError::Full(_)) => {
+                                    sub.skipped.set(sub.skipped.get() + 1);
+                                    true
+                                }
+                                Err(TrySendError::Disconnected(_)) => false
+                            }
+                        });
+
+                        for pool in pools.values_mut() {
+                            if !(pool.filter)(&value) {
+                                continue;
+                            }
+
+                            while !pool.members.is_empty() {
+                                if pool.cursor >= pool.members.len() {
+                                    pool.cursor = 0;
+                                }
+                                let idx = pool.cursor;
+                                match pool.members[idx].send(value.clone()) {
+                                    Ok(()) => {
+                                        pool.cursor = (idx + 1) % pool.members.len();
+                                        break;
+                                    }
+                                    Err(_) => {
+                                        pool.members.remove(idx);
+                                    }
+                                }
+                            }
+                        }
+
                         forward_s = if let Some(forward) = forward_s {
                             if forward.send(value).is_err() {
                                 None
@@ -94,30 +242,55 @@ impl <T: Clone + Send + 'static> Requester<T> {
                             None
                         };
                     }
-                    Err(Empty) => thread::yield_now(),
-                    Err(Disconnected) => return
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => return
                 }
 
-                if no_more_subscribers &&
+                // Get rid of timed out receivers, even when no new value
+                // arrived to trigger the retain above.
+                let current = precise_time_ms();
+                with_timeouts.retain(|&(deadline, _, _)| deadline >= current);
+
+                if live_sources == 0 &&
                    without_timeouts.is_empty() &&
                    with_timeouts.is_empty() &&
+                   bounded.is_empty() &&
+                   pools.values().all(|pool| pool.members.is_empty()) &&
                    forward_s.is_none() {
                     return;
                 }
             }
         });
 
-        (Requester {
-            subscribe_no_timeout: nt_s,
-            subscribe_timeout: t_s
-        }, forward_r)
+        (Requester { msg_s: msg_s, next_pool_id: Arc::new(AtomicUsize::new(0)) }, forward_r)
+    }
+
+    /// Creates a Requester together with a fresh source channel, for callers that don't already
+    /// have a `Receiver<T>` to pass to `Requester::new`.
+    ///
+    /// Returns the sending half of that channel, the Requester, and the forwarding Receiver<T>
+    /// described in `Requester::new`. Because `Sender<T>` is `Clone`, this sending half can be
+    /// cloned across any number of producer threads.
+    pub fn channel() -> (Sender<T>, Requester<T>, Receiver<T>) {
+        let (tx, rx) = channel();
+        let (requester, forward_r) = Requester::new(rx);
+        (tx, requester, forward_r)
+    }
+
+    /// Merges another source of items into this already-running Requester.
+    ///
+    /// Values received from `source` are interleaved with those from the original source (and
+    /// any other sources added this way) and distributed to subscribers identically.
+    pub fn add_source(&self, source: Receiver<T>) {
+        self.msg_s.send(Msg::SourceAdded).unwrap();
+        spawn_forwarder(source, self.msg_s.clone());
     }
 
     /// Returns a Receiver<T> where for each element, `predicate(t) == true`.
     pub fn request<F>(&self, predicate: F) -> Receiver<T> where F: Fn(&T) -> bool + Send + 'static{
         let boxed = Box::new(predicate) as FilterFn<T>;
         let (sx, rx) = channel();
-        self.subscribe_no_timeout.send((boxed, sx)).unwrap();
+        self.msg_s.send(Msg::Sub(boxed, sx)).unwrap();
         rx
     }
 
@@ -128,7 +301,69 @@ impl <T: Clone + Send + 'static> Requester<T> {
         let deadline = precise_time_ms() + timeout_ms;
         let boxed = Box::new(predicate) as FilterFn<T>;
         let (sx, rx) = channel();
-        self.subscribe_timeout.send((deadline, boxed, sx)).unwrap();
+        self.msg_s.send(Msg::SubTimeout(deadline, boxed, sx)).unwrap();
+        rx
+    }
+
+    /// Returns a Receiver<T> where for each element, `predicate(t) == true`.
+    ///
+    /// Unlike `request`, if the most recent value to pass through before this call matches
+    /// `predicate`, it is delivered immediately so the subscriber starts with a usable current
+    /// state instead of waiting for the next matching event. Only that single most recent value
+    /// is kept; an earlier match followed by a non-matching value is not delivered.
+    ///
+    /// The cache this relies on is only kept once `request_latest` has been called at least
+    /// once, so normal `request`/`request_timeout` subscribers never pay for the extra clone.
+    /// One consequence: the very first `request_latest` call on a given `Requester` arms the
+    /// cache but cannot see values that passed before it, so it behaves like `request` until
+    /// the next matching value arrives; later calls see whatever the cache last saw.
+    pub fn request_latest<F>(&self, predicate: F) -> Receiver<T> where F: Fn(&T) -> bool + Send + 'static {
+        let boxed = Box::new(predicate) as FilterFn<T>;
+        let (sx, rx) = channel();
+        self.msg_s.send(Msg::SubLatest(boxed, sx)).unwrap();
         rx
     }
+
+    /// Returns a bounded Receiver<Result<T, Lagged>> where for each element, `predicate(t) == true`.
+    ///
+    /// At most `capacity` matching values are held for this subscriber at a time. If the
+    /// consumer falls behind, further matches are dropped instead of growing memory without
+    /// bound; once there is room again an `Err(Lagged { skipped })` reporting how many were
+    /// dropped is sent ahead of the next matching value (see `Lagged`).
+    pub fn request_bounded<F>(&self, capacity: usize, predicate: F) -> Receiver<Result<T, Lagged>> where F: Fn(&T) -> bool + Send + 'static {
+        let boxed = Box::new(predicate) as FilterFn<T>;
+        let (sx, rx) = sync_channel(capacity);
+        self.msg_s.send(Msg::SubBounded(boxed, sx)).unwrap();
+        rx
+    }
+
+    /// Returns a PoolHandle<T> for work distribution: each value where `predicate(t) == true`
+    /// is delivered to exactly one of the pool's workers (round-robin) rather than to all of
+    /// them, for load-balancing across a pool of consumers instead of broadcasting. A matching
+    /// value that arrives while the pool has no live worker is not delivered to any worker.
+    pub fn request_pool<F>(&self, predicate: F) -> PoolHandle<T> where F: Fn(&T) -> bool + Send + 'static {
+        let pool_id = self.next_pool_id.fetch_add(1, Ordering::Relaxed);
+        let boxed = Box::new(predicate) as FilterFn<T>;
+        self.msg_s.send(Msg::PoolNew(pool_id, boxed)).unwrap();
+        PoolHandle { pool_id: pool_id, msg_s: self.msg_s.clone() }
+    }
+
+    /// Blocks until the first element where `predicate(t) == true` arrives, then returns it.
+    ///
+    /// Panics if the source is exhausted before a match arrives.
+    pub fn request_one<F>(&self, predicate: F) -> T where F: Fn(&T) -> bool + Send + 'static {
+        let rx = self.request(predicate);
+        rx.recv().unwrap()
+    }
+
+    /// Blocks until either the first element where `predicate(t) == true` arrives, or
+    /// `timeout_ms` elapses, mirroring `std::sync::mpsc::RecvTimeoutError`: `Timeout` means no
+    /// match arrived in time, `Disconnected` means the source is gone.
+    pub fn request_one_timeout<F>(&self, timeout_ms: u64, predicate: F) -> Result<T, RecvTimeoutError> where F: Fn(&T) -> bool + Send + 'static {
+        // Use a plain (undeadlined) subscription and let this recv_timeout be the only
+        // clock that matters — `request_timeout` would race its own dispatcher-side deadline
+        // against this one and could report Disconnected instead of Timeout.
+        let rx = self.request(predicate);
+        rx.recv_timeout(Duration::from_millis(timeout_ms))
+    }
 }